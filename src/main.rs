@@ -1,9 +1,16 @@
-use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+use base64::{
+    engine::general_purpose::{URL_SAFE, URL_SAFE_NO_PAD},
+    Engine as _,
+};
 use clap::Parser;
 use std::{
-    env, io,
+    env,
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use sha2::{Digest, Sha256};
 use ureq::Response;
 
 extern crate confy;
@@ -11,6 +18,18 @@ extern crate confy;
 #[macro_use]
 extern crate serde_derive;
 
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+enum AuthMode {
+    Confidential,
+    Pkce,
+}
+
+impl Default for AuthMode {
+    fn default() -> Self {
+        AuthMode::Confidential
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
     client_id: String,
@@ -19,6 +38,8 @@ struct Config {
     access_token: String,
     refresh_token: String,
     expires_at: u64,
+    #[serde(default)]
+    auth_mode: AuthMode,
 }
 
 #[derive(Debug, Deserialize)]
@@ -32,6 +53,7 @@ struct TokenResponse {
 struct RefreshTokenResponse {
     access_token: String,
     expires_in: u64,
+    refresh_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,6 +62,17 @@ struct ResponseError {
     error_description: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct WebApiError {
+    status: u16,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct WebApiErrorResponse {
+    error: WebApiError,
+}
+
 #[derive(Serialize, Deserialize)]
 struct SpotifyAlbumImage {
     url: String,
@@ -63,11 +96,14 @@ struct SpotifyItem {
     artists: Vec<SpotifyArtist>,
     album: SpotifyAlbum,
     name: String,
+    duration_ms: Option<u64>,
+    explicit: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct PlaybackState {
     is_playing: bool,
+    progress_ms: Option<u64>,
     item: SpotifyItem,
 }
 
@@ -80,6 +116,7 @@ impl Default for Config {
             access_token: String::from(""),
             refresh_token: String::from(""),
             expires_at: 0,
+            auth_mode: AuthMode::Confidential,
         }
     }
 }
@@ -94,6 +131,34 @@ struct Args {
     /// Use this flag to output the information to a file in JSON format
     #[arg(short, long)]
     json: bool,
+
+    /// Load and store credentials at this path instead of the default config file
+    #[arg(short, long)]
+    config: Option<String>,
+
+    /// Expand a custom output template, e.g. "{title} - {artists} ({progress}/{duration})"
+    #[arg(short, long)]
+    format: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    /// Resume playback on the active device
+    Play,
+    /// Pause playback on the active device
+    Pause,
+    /// Skip to the next track
+    Next,
+    /// Skip to the previous track
+    Previous,
+    /// Set the playback volume as a percentage (0-100)
+    Volume {
+        #[arg(value_parser = clap::value_parser!(u8).range(0..=100))]
+        percent: u8,
+    },
 }
 
 #[derive(PartialEq)]
@@ -101,42 +166,180 @@ enum Action {
     DEFAULT,
     SETUP,
     PLAYBACK,
+    CONTROL,
 }
 
-fn config_load(custom_path: Option<String>) -> Result<Config, String> {
-    if let Some(_) = custom_path {
-        Err(String::from("no impl"))
-    } else {
-        let cfg: Config = confy::load("tail_spotify", None).map_err(|e| e.to_string())?;
-        let file =
-            confy::get_configuration_file_path("tail_spotify", None).map_err(|e| e.to_string())?;
-        Ok(cfg)
+fn config_load(custom_path: Option<String>) -> Result<(Config, bool), String> {
+    let mut cfg: Config = match &custom_path {
+        Some(path) => confy::load_path(path).map_err(|e| e.to_string())?,
+        None => confy::load("tail_spotify", None).map_err(|e| e.to_string())?,
+    };
+    let mut from_env = false;
+    if cfg.client_id == "" {
+        if let Ok(value) = env::var("TAIL_CLIENT_ID") {
+            cfg.client_id = value;
+            from_env = true;
+        }
+    }
+    if cfg.client_secret == "" {
+        if let Ok(value) = env::var("TAIL_CLIENT_SECRET") {
+            cfg.client_secret = value;
+            from_env = true;
+        }
     }
+    if cfg.redirect_uri == "" {
+        if let Ok(value) = env::var("TAIL_REDIRECT_URI") {
+            cfg.redirect_uri = value;
+            from_env = true;
+        }
+    }
+    Ok((cfg, from_env))
 }
 
 fn config_save(custom_path: Option<String>, config: Config) -> Result<(), String> {
-    if let Some(_) = custom_path {
-        Err(String::from("no impl"))
+    match &custom_path {
+        Some(path) => confy::store_path(path, config).map_err(|e| e.to_string())?,
+        None => confy::store("tail_spotify", None, config).map_err(|e| e.to_string())?,
+    }
+    Ok(())
+}
+
+fn random_urlsafe(bytes: usize) -> Result<String, String> {
+    let mut buf = vec![0u8; bytes];
+    let mut source = File::open("/dev/urandom").map_err(|e| e.to_string())?;
+    source.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    Ok(URL_SAFE_NO_PAD.encode(buf))
+}
+
+fn redirect_authority(redirect_uri: &str) -> Result<String, String> {
+    let without_scheme = redirect_uri.split("://").nth(1).unwrap_or(redirect_uri);
+    let authority = without_scheme.split('/').next().unwrap_or("");
+    if authority.contains(':') {
+        Ok(authority.to_string())
     } else {
-        confy::store("tail_spotify", None, config).map_err(|e| e.to_string())?;
-        Ok(())
+        Err(String::from(
+            "the redirect_uri must include an explicit loopback host and port (e.g. http://127.0.0.1:8888/callback)",
+        ))
     }
 }
 
-fn get_tokens(auth_code: String, config: &mut Config) -> Result<TokenResponse, String> {
-    let request = ureq::post("https://accounts.spotify.com/api/token")
-        .set(
-            "Authorization",
-            &format!(
-                "Basic {}",
-                URL_SAFE.encode(format!("{}:{}", config.client_id, config.client_secret))
-            ),
-        )
-        .send_form(&[
+fn open_browser(url: &str) {
+    let opener = if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(target_os = "windows") {
+        "explorer"
+    } else {
+        "xdg-open"
+    };
+    let _ = std::process::Command::new(opener).arg(url).spawn();
+}
+
+fn wait_for_redirect(redirect_uri: &str, expected_state: &str) -> Result<String, String> {
+    let listener = TcpListener::bind(redirect_authority(redirect_uri)?).map_err(|e| e.to_string())?;
+    loop {
+        let (mut stream, _) = listener.accept().map_err(|e| e.to_string())?;
+        let mut request_line = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut request_line)
+            .map_err(|e| e.to_string())?;
+        let query = request_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|target| target.split('?').nth(1))
+            .unwrap_or("");
+        let mut code: Option<String> = None;
+        let mut state: Option<String> = None;
+        for pair in query.split('&') {
+            match pair.split_once('=') {
+                Some(("code", value)) => code = Some(value.to_string()),
+                Some(("state", value)) => state = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        // Favicon/preconnect and other stray hits carry no code; answer them and
+        // keep waiting for the request that actually carries the redirect.
+        if code.is_none() && state.is_none() {
+            let _ = stream.write_all(
+                b"HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n",
+            );
+            continue;
+        }
+        let body = if code.is_some() && state.as_deref() == Some(expected_state) {
+            "<html><body>You may close this tab.</body></html>"
+        } else {
+            "<html><body>Authorization failed, please try again.</body></html>"
+        };
+        let _ = stream.write_all(
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        );
+        if state.as_deref() != Some(expected_state) {
+            return Err(String::from(
+                "the state returned by Spotify did not match, aborting to guard against CSRF",
+            ));
+        }
+        return code.ok_or_else(|| String::from("Spotify did not return an authorization code"));
+    }
+}
+
+fn with_rate_limit<F>(request: F) -> Result<Response, ureq::Error>
+where
+    F: Fn() -> Result<Response, ureq::Error>,
+{
+    let max_attempts = 5;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let result = request();
+        if let Err(ureq::Error::Status(429, response)) = &result {
+            if attempt < max_attempts {
+                let retry_after = response
+                    .header("Retry-After")
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or(5);
+                std::thread::sleep(Duration::from_secs(retry_after));
+                continue;
+            }
+        }
+        return result;
+    }
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn get_tokens(
+    auth_code: String,
+    config: &mut Config,
+    code_verifier: Option<&str>,
+) -> Result<TokenResponse, String> {
+    let request = with_rate_limit(|| match code_verifier {
+        Some(verifier) => ureq::post("https://accounts.spotify.com/api/token").send_form(&[
+            ("client_id", &config.client_id),
             ("grant_type", "authorization_code"),
             ("code", &auth_code),
             ("redirect_uri", &config.redirect_uri),
-        ]);
+            ("code_verifier", verifier),
+        ]),
+        None => ureq::post("https://accounts.spotify.com/api/token")
+            .set(
+                "Authorization",
+                &format!(
+                    "Basic {}",
+                    URL_SAFE.encode(format!("{}:{}", config.client_id, config.client_secret))
+                ),
+            )
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", &auth_code),
+                ("redirect_uri", &config.redirect_uri),
+            ]),
+    });
     let failed_response: Option<Response>;
     match request {
         Ok(response) => {
@@ -153,24 +356,34 @@ fn get_tokens(auth_code: String, config: &mut Config) -> Result<TokenResponse, S
 
 fn refresh_tokens(config: &mut Config) -> Result<(), String> {
     if config.access_token != "" && config.refresh_token != "" && config.expires_at != 0 {
-        let request = ureq::post("https://accounts.spotify.com/api/token")
-            .set(
-                "Authorization",
-                &format!(
-                    "Basic {}",
-                    URL_SAFE.encode(format!("{}:{}", config.client_id, config.client_secret))
-                ),
-            )
-            .send_form(&[
+        let request = with_rate_limit(|| match config.auth_mode {
+            AuthMode::Pkce => ureq::post("https://accounts.spotify.com/api/token").send_form(&[
+                ("client_id", &config.client_id),
                 ("grant_type", "refresh_token"),
                 ("refresh_token", &config.refresh_token),
-            ]);
+            ]),
+            AuthMode::Confidential => ureq::post("https://accounts.spotify.com/api/token")
+                .set(
+                    "Authorization",
+                    &format!(
+                        "Basic {}",
+                        URL_SAFE.encode(format!("{}:{}", config.client_id, config.client_secret))
+                    ),
+                )
+                .send_form(&[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &config.refresh_token),
+                ]),
+        });
         let failed_response: Option<Response>;
         match request {
             Ok(response) => {
                 let refresh_data: RefreshTokenResponse =
                     response.into_json().map_err(|e| e.to_string())?;
                 config.access_token = refresh_data.access_token;
+                if let Some(refresh_token) = refresh_data.refresh_token {
+                    config.refresh_token = refresh_token;
+                }
                 let expiry_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
                     + Duration::from_secs(refresh_data.expires_in);
                 config.expires_at = expiry_time.as_secs();
@@ -192,9 +405,11 @@ fn get_playback(config: &mut Config) -> Result<Option<PlaybackState>, String> {
     let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
     let expiry_time = Duration::from_secs(config.expires_at);
     if current_time < expiry_time {
-        let request = ureq::get("https://api.spotify.com/v1/me/player")
-            .set("Authorization", &format!("Bearer {}", config.access_token))
-            .call();
+        let request = with_rate_limit(|| {
+            ureq::get("https://api.spotify.com/v1/me/player")
+                .set("Authorization", &format!("Bearer {}", config.access_token))
+                .call()
+        });
         let failed_response: Option<Response>;
         match request {
             Ok(response) => match response.status() {
@@ -214,28 +429,118 @@ fn get_playback(config: &mut Config) -> Result<Option<PlaybackState>, String> {
     return Err(String::from("Unknown Error"));
 }
 
+fn format_duration(milliseconds: Option<u64>) -> String {
+    match milliseconds {
+        Some(milliseconds) => {
+            let total_seconds = milliseconds / 1000;
+            format!("{}:{:02}", total_seconds / 60, total_seconds % 60)
+        }
+        None => String::from("-"),
+    }
+}
+
+fn format_playback(template: &str, playback: &PlaybackState) -> String {
+    let artists = playback
+        .item
+        .artists
+        .iter()
+        .map(|artist| artist.name.clone())
+        .collect::<Vec<String>>()
+        .join(", ");
+    let art_url = playback
+        .item
+        .album
+        .images
+        .first()
+        .map(|image| image.url.clone())
+        .unwrap_or_default();
+    template
+        .replace("{title}", &playback.item.name)
+        .replace("{artists}", &artists)
+        .replace("{album}", &playback.item.album.name)
+        .replace("{art_url}", &art_url)
+        .replace("{progress}", &format_duration(playback.progress_ms))
+        .replace("{duration}", &format_duration(playback.item.duration_ms))
+}
+
+fn control_playback(config: &mut Config, method: &str, endpoint: &str) -> Result<(), String> {
+    let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let expiry_time = Duration::from_secs(config.expires_at);
+    if current_time >= expiry_time {
+        refresh_tokens(config)?;
+    }
+    let url = format!("https://api.spotify.com/v1/me/player{}", endpoint);
+    let request = with_rate_limit(|| {
+        ureq::request(method, &url)
+            .set("Authorization", &format!("Bearer {}", config.access_token))
+            .call()
+    });
+    let failed_response: Option<Response>;
+    match request {
+        Ok(_) => return Ok(()),
+        Err(response_err) => failed_response = response_err.into_response(),
+    }
+    if let Some(response) = failed_response {
+        let error_data: WebApiErrorResponse = response.into_json().map_err(|e| e.to_string())?;
+        return Err(format!("Spotify returned the following while controlling playback on behalf of your account: {} ({}), please try again.", error_data.error.message, error_data.error.status));
+    }
+    return Err(String::from("Unknown Error"));
+}
+
 fn main() {
     let args = Args::parse();
-    let mut config_path: Option<String> = None;
+    let config_path: Option<String> = args.config.clone();
     let mut mode: Action = Action::DEFAULT;
     if args.setup {
         mode = Action::SETUP
+    } else if args.command.is_some() {
+        mode = Action::CONTROL;
     } else if args.json {
         mode = Action::PLAYBACK;
     }
-    let config = config_load(config_path);
-    if let Ok(mut cfg) = config {
+    let config = config_load(config_path.clone());
+    // Outside of setup, credentials pulled from the environment with no explicit --config
+    // are not written back into the default config file; setup always saves the config.
+    let skip_token_persist = match &config {
+        Ok((_, from_env)) => *from_env && config_path.is_none(),
+        Err(_) => false,
+    };
+    if let Ok((mut cfg, _)) = config {
         if mode == Action::SETUP || (cfg.access_token == "" || cfg.refresh_token == "") {
-            println!("Authroize your Spotify account via: https://accounts.spotify.com/authorize?client_id={}&response_type=code&redirect_uri={}&scope=user-read-currently-playing%20user-read-playback-state", cfg.client_id, cfg.redirect_uri);
-            let mut auth_code = String::new();
-            match io::stdin().read_line(&mut auth_code) {
-                Ok(_) => auth_code = auth_code.trim().to_string(),
-                Err(_) => {}
-            }
-            if auth_code == "" {
-                panic!("There was an error parsing your input");
-            }
-            match get_tokens(auth_code, &mut cfg) {
+            let state = match random_urlsafe(16) {
+                Ok(state) => state,
+                Err(state_err) => panic!("There was an error generating a login state: {}", state_err),
+            };
+            cfg.auth_mode = if cfg.client_secret == "" {
+                AuthMode::Pkce
+            } else {
+                AuthMode::Confidential
+            };
+            let code_verifier = if cfg.auth_mode == AuthMode::Pkce {
+                match random_urlsafe(32) {
+                    Ok(verifier) => Some(verifier),
+                    Err(verifier_err) => {
+                        panic!("There was an error generating a code verifier: {}", verifier_err)
+                    }
+                }
+            } else {
+                None
+            };
+            let pkce_params = match &code_verifier {
+                Some(verifier) => format!(
+                    "&code_challenge_method=S256&code_challenge={}",
+                    code_challenge(verifier)
+                ),
+                None => String::new(),
+            };
+            let authorize_url = format!("https://accounts.spotify.com/authorize?client_id={}&response_type=code&redirect_uri={}&state={}{}&scope=user-read-currently-playing%20user-read-playback-state%20user-modify-playback-state", cfg.client_id, cfg.redirect_uri, state, pkce_params);
+            println!("Authroize your Spotify account via: {}", authorize_url);
+            open_browser(&authorize_url);
+            let auth_code = match wait_for_redirect(&cfg.redirect_uri, &state) {
+                Ok(auth_code) => auth_code,
+                Err(redirect_err) => panic!("There was an error capturing the redirect: {}", redirect_err),
+            };
+            match get_tokens(auth_code, &mut cfg, code_verifier.as_deref()) {
                 Ok(token_data) => {
                     cfg.access_token = token_data.access_token.clone();
                     cfg.refresh_token = token_data.refresh_token.clone();
@@ -247,7 +552,7 @@ fn main() {
                     panic!("Error: {}", auth_err);
                 }
             }
-            match config_save(None, cfg) {
+            match config_save(config_path.clone(), cfg) {
                 Ok(_) => {
                     println!("Config Saved.")
                 }
@@ -258,16 +563,45 @@ fn main() {
                     );
                 }
             }
+        } else if mode == Action::CONTROL {
+            let (method, endpoint) = match args.command.as_ref().unwrap() {
+                Command::Play => ("PUT", String::from("/play")),
+                Command::Pause => ("PUT", String::from("/pause")),
+                Command::Next => ("POST", String::from("/next")),
+                Command::Previous => ("POST", String::from("/previous")),
+                Command::Volume { percent } => {
+                    ("PUT", format!("/volume?volume_percent={}", percent))
+                }
+            };
+            let control = control_playback(&mut cfg, method, &endpoint);
+            if !skip_token_persist {
+                let cfg_status = config_save(config_path.clone(), cfg);
+                match cfg_status {
+                    Ok(_) => {}
+                    Err(cfg_err) => {
+                        panic!(
+                            "There was an error while saving the config file: {}",
+                            cfg_err
+                        );
+                    }
+                }
+            }
+            match control {
+                Ok(_) => {}
+                Err(control_err) => println!("{}", control_err),
+            }
         } else if mode == Action::DEFAULT || mode == Action::PLAYBACK {
             let playback = get_playback(&mut cfg);
-            let cfg_status = config_save(None, cfg);
-            match cfg_status {
-                Ok(_) => {}
-                Err(cfg_err) => {
-                    panic!(
-                        "There was an error while saving the config file: {}",
-                        cfg_err
-                    );
+            if !skip_token_persist {
+                let cfg_status = config_save(config_path.clone(), cfg);
+                match cfg_status {
+                    Ok(_) => {}
+                    Err(cfg_err) => {
+                        panic!(
+                            "There was an error while saving the config file: {}",
+                            cfg_err
+                        );
+                    }
                 }
             }
             match playback {
@@ -275,10 +609,16 @@ fn main() {
                     match playback_status {
                         Some(playback_data) => {
                             if mode == Action::DEFAULT && playback_data.is_playing {
-                                println!(
-                                    "{} - {}",
-                                    playback_data.item.name, playback_data.item.artists[0].name
-                                );
+                                match &args.format {
+                                    Some(template) => {
+                                        println!("{}", format_playback(template, &playback_data))
+                                    }
+                                    None => println!(
+                                        "{} - {}",
+                                        playback_data.item.name,
+                                        playback_data.item.artists[0].name
+                                    ),
+                                }
                             } else if mode == Action::PLAYBACK {
                                 let stringified: Result<String, String> =
                                     serde_json::to_string(&playback_data)